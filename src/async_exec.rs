@@ -0,0 +1,451 @@
+//! A futures-based counterpart to the blocking API in the rest of this
+//! crate, gated behind the `async` feature so that callers who don't need
+//! it aren't forced to pull in `futures`, `tokio-core`, `tokio-process`,
+//! and `tokio-io`.
+//!
+//! `Expression::start_async` walks the exact same expression tree as
+//! `Expression::exec`, reusing `IoContext`, `StageResult`, `combine`, and
+//! `resolve_env` from the blocking side. The only thing that changes is
+//! *how* a leaf command is driven to completion: instead of blocking the
+//! calling thread on `Child::wait` and spinning up a thread each for
+//! `input()` and `*_capture()`, a leaf is spawned with
+//! `tokio_process::CommandExt::spawn_async`, and its stdin/stdout/stderr
+//! pipes are driven with `tokio_io::io::write_all`/`read_to_end` against
+//! the reactor behind the `&Handle` the caller supplies. Because both
+//! walks share `combine` and `resolve_env`, the checked/unchecked
+//! precedence across `pipe`/`then` and the environment-override precedence
+//! are identical between the sync and async paths. Broken-pipe suppression
+//! on the input side reuses `suppress_broken_pipe_errors`, and stdout/
+//! stderr swapping is handled by the same `IoContext` field-copying that
+//! `exec_io` does, since it happens before any process is spawned.
+
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_process;
+
+use self::futures::{future, Future};
+use self::tokio_io::io::{read_to_end, write_all};
+use self::tokio_process::CommandExt;
+
+use std::ffi::{OsStr, OsString};
+use std::process::{Command, Output};
+use std::sync::Arc;
+#[cfg(all(unix, feature = "jobserver"))]
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use super::{
+    combine, pipe, resolve_env, suppress_broken_pipe_errors, Error, ErrorKind, Expression,
+    ExpressionInner, IoContext, IoExpression, IoValue, Result, StageResult,
+};
+#[cfg(all(unix, feature = "jobserver"))]
+use super::jobserver;
+
+/// Re-exported so callers can name the handle type `start_async` expects
+/// without depending on `tokio-core` directly.
+pub use self::tokio_core::reactor::Handle as ReactorHandle;
+
+type BoxStageFuture = Box<Future<Item = StageResult, Error = Error> + Send>;
+type BoxCaptureFuture = Box<Future<Item = Vec<u8>, Error = Error> + Send>;
+
+/// The async counterpart to `ExecOutput`: futures for the (possibly
+/// compound) stage's combined status and its captured output, instead of
+/// already-spawned threads.
+struct AsyncExecOutput {
+    stage: BoxStageFuture,
+    stdout_capture: Option<BoxCaptureFuture>,
+    stderr_capture: Option<BoxCaptureFuture>,
+    // The `.timeout(...)` in effect for this subtree, if any, bubbled up so
+    // `start_async` (the only place that knows it's looking at the root of
+    // the whole expression) can refuse to silently ignore it. See the
+    // comment on `start_async`.
+    timeout: Option<Duration>,
+}
+
+fn capture_or_empty(capture: Option<BoxCaptureFuture>) -> BoxCaptureFuture {
+    capture.unwrap_or_else(|| Box::new(future::ok(Vec::new())))
+}
+
+impl Expression {
+    /// The async counterpart to `start()`/`run()`. Spawns the same tree of
+    /// child processes, registering their stdio with `handle`'s reactor
+    /// instead of blocking the calling thread or spinning up helper
+    /// threads, and returns a future that resolves the same way `run()`
+    /// does: `Ok(output)` on a successful (or unchecked) status, or
+    /// `Err(ErrorKind::Status(output))` on a checked failure.
+    ///
+    /// `.timeout(...)` is not supported on this path: the blocking API
+    /// enforces it with a watchdog thread that calls `Handle::kill()`, but
+    /// there's no equivalent handle into a still-running async tree to
+    /// kill. An expression with a `.timeout(...)` applied to the whole
+    /// expression, or anywhere in it outside the two sides of a `then`,
+    /// makes this return an error instead of a future. That check is made
+    /// after the leaf commands outside any `then` have been spawned, so
+    /// those commands are started before the error is returned. A
+    /// `.timeout(...)` inside either side of a `then` is not detected and
+    /// has no effect.
+    pub fn start_async(
+        &self,
+        handle: &ReactorHandle,
+    ) -> Result<Box<Future<Item = Output, Error = Error> + Send>> {
+        let exec_output = self.exec_async(IoContext::root(), handle)?;
+        if exec_output.timeout.is_some() {
+            bail!("`.timeout(...)` is not supported by `start_async`");
+        }
+        let stdout_capture = capture_or_empty(exec_output.stdout_capture);
+        let stderr_capture = capture_or_empty(exec_output.stderr_capture);
+        Ok(Box::new(
+            exec_output
+                .stage
+                .join3(stdout_capture, stderr_capture)
+                .and_then(|(result, stdout, stderr)| {
+                    let output = Output {
+                        status: result.status,
+                        stdout: stdout,
+                        stderr: stderr,
+                    };
+                    if result.checked && !result.status.success() {
+                        return Err(ErrorKind::Status(output).into());
+                    }
+                    Ok(output)
+                }),
+        ))
+    }
+
+    fn exec_async(&self, context: IoContext, handle: &ReactorHandle) -> Result<AsyncExecOutput> {
+        match *self.0 {
+            ExpressionInner::Cmd(ref argv) => exec_argv_async(argv, context, handle),
+            ExpressionInner::Sh(ref command) => exec_sh_async(command, context, handle),
+            ExpressionInner::Pipe(ref left, ref right) => {
+                exec_pipe_async(left, right, context, handle)
+            }
+            ExpressionInner::Then(ref left, ref right) => {
+                exec_then_async(left, right, context, handle)
+            }
+            ExpressionInner::Io(ref io, ref inner) => exec_io_async(io, inner, context, handle),
+        }
+    }
+}
+
+fn exec_argv_async(
+    argv: &[OsString],
+    context: IoContext,
+    handle: &ReactorHandle,
+) -> Result<AsyncExecOutput> {
+    let mut command = Command::new(&argv[0]);
+    command.args(&argv[1..]);
+    if let Some(ref dir) = context.dir {
+        command.current_dir(dir);
+    }
+    command.env_clear();
+    #[allow(unused_mut)]
+    let mut env = resolve_env(&context.env_ops);
+
+    #[cfg(all(unix, feature = "jobserver"))]
+    let effective_jobserver = context.jobserver.clone().or_else(jobserver::default_jobserver);
+    #[cfg(all(unix, feature = "jobserver"))]
+    {
+        if let Some(ref js) = effective_jobserver {
+            env.insert(OsString::from("MAKEFLAGS"), js.makeflags());
+        }
+    }
+    command.envs(env);
+
+    let (stdin_stdio, input_bytes) = context.stdin.into_input_stdio()?;
+    command.stdin(stdin_stdio);
+    command.stdout(context.stdout.into_output_stdio()?);
+    command.stderr(context.stderr.into_output_stdio()?);
+
+    // Every leaf is checked by default; `.unchecked()` marks its subtree's
+    // result as unchecked after the fact instead, the same way the
+    // blocking path does -- see the comment on `IoContext` in lib.rs.
+    let checked = true;
+
+    // Same acquire-before-spawn dance as the blocking path (see the
+    // comment in `exec_argv`): the first leaf anywhere in the tree to reach
+    // this point takes the implicit slot instead of acquiring one, so a
+    // self-contained expression can never deadlock waiting on itself.
+    #[cfg(all(unix, feature = "jobserver"))]
+    let jobserver_token = match effective_jobserver {
+        Some(ref js) => {
+            js.inherit_for_spawn(&mut command);
+            if context.jobserver_implicit_slot.swap(false, Ordering::SeqCst) {
+                None
+            } else {
+                Some(js.acquire()?)
+            }
+        }
+        None => None,
+    };
+
+    let mut child = command.spawn_async(handle)?;
+
+    let input_future: Box<Future<Item = (), Error = Error> + Send> = match input_bytes {
+        Some(bytes) => {
+            let stdin_pipe = child.stdin().take().expect("stdin was piped for input()");
+            Box::new(
+                write_all(stdin_pipe, (*bytes).clone())
+                    .then(|result| suppress_broken_pipe_errors(result.map(|_| ())))
+                    .map_err(Error::from),
+            )
+        }
+        None => Box::new(future::ok(())),
+    };
+
+    let stage: BoxStageFuture = Box::new(
+        child
+            .map_err(Error::from)
+            .join(input_future)
+            .map(move |(status, ())| {
+                // Hold the token alive until the child has actually
+                // exited, releasing it back to the pool on drop here.
+                #[cfg(all(unix, feature = "jobserver"))]
+                let _jobserver_token = jobserver_token;
+                StageResult {
+                    status: status,
+                    checked: checked,
+                }
+            }),
+    );
+
+    Ok(AsyncExecOutput {
+        stage: stage,
+        stdout_capture: None,
+        stderr_capture: None,
+        timeout: context.timeout,
+    })
+}
+
+#[cfg(unix)]
+fn exec_sh_async(
+    command: &OsStr,
+    context: IoContext,
+    handle: &ReactorHandle,
+) -> Result<AsyncExecOutput> {
+    let argv = vec![OsString::from("sh"), OsString::from("-c"), command.to_owned()];
+    exec_argv_async(&argv, context, handle)
+}
+
+#[cfg(windows)]
+fn exec_sh_async(
+    command: &OsStr,
+    context: IoContext,
+    handle: &ReactorHandle,
+) -> Result<AsyncExecOutput> {
+    let argv = vec![OsString::from("cmd"), OsString::from("/C"), command.to_owned()];
+    exec_argv_async(&argv, context, handle)
+}
+
+fn exec_pipe_async(
+    left: &Expression,
+    right: &Expression,
+    context: IoContext,
+    handle: &ReactorHandle,
+) -> Result<AsyncExecOutput> {
+    let (reader, writer) = pipe()?;
+    let left_context = IoContext {
+        stdin: context.stdin.try_clone()?,
+        stdout: IoValue::Writer(writer),
+        stderr: context.stderr.try_clone()?,
+        dir: context.dir.clone(),
+        env_ops: context.env_ops.clone(),
+        timeout: context.timeout,
+        #[cfg(all(unix, feature = "jobserver"))]
+        jobserver: context.jobserver.clone(),
+        #[cfg(all(unix, feature = "jobserver"))]
+        jobserver_implicit_slot: context.jobserver_implicit_slot.clone(),
+    };
+    let right_context = IoContext {
+        stdin: IoValue::Reader(reader),
+        stdout: context.stdout.try_clone()?,
+        stderr: context.stderr.try_clone()?,
+        dir: context.dir.clone(),
+        env_ops: context.env_ops.clone(),
+        timeout: context.timeout,
+        #[cfg(all(unix, feature = "jobserver"))]
+        jobserver: context.jobserver.clone(),
+        #[cfg(all(unix, feature = "jobserver"))]
+        jobserver_implicit_slot: context.jobserver_implicit_slot.clone(),
+    };
+    let left_out = left.exec_async(left_context, handle)?;
+    let right_out = right.exec_async(right_context, handle)?;
+    let stage: BoxStageFuture = Box::new(
+        left_out
+            .stage
+            .join(right_out.stage)
+            .map(|(l, r)| combine(l, r)),
+    );
+    Ok(AsyncExecOutput {
+        stage: stage,
+        stdout_capture: left_out.stdout_capture.or(right_out.stdout_capture),
+        stderr_capture: left_out.stderr_capture.or(right_out.stderr_capture),
+        timeout: left_out.timeout.or(right_out.timeout),
+    })
+}
+
+/// Everything the right side of a `then` needs once the left side has
+/// finished: the combined status, plus the fully concatenated stdout/stderr
+/// captures. Computed as a single `Shared` future so the three views
+/// returned to our caller (`stage`, `stdout_capture`, `stderr_capture`) can
+/// each subscribe to it without spawning the right-hand side more than
+/// once.
+type ThenResult = (StageResult, Vec<u8>, Vec<u8>);
+
+fn exec_then_async(
+    left: &Expression,
+    right: &Expression,
+    context: IoContext,
+    handle: &ReactorHandle,
+) -> Result<AsyncExecOutput> {
+    let left_context = context.try_clone()?;
+    let left_out = left.exec_async(left_context, handle)?;
+    let left_stdout = capture_or_empty(left_out.stdout_capture);
+    let left_stderr = capture_or_empty(left_out.stderr_capture);
+
+    // The right side isn't spawned until later, so its own `.timeout(...)`
+    // nodes (if any) aren't visible yet; this only reflects a timeout set
+    // at or above this `then`, which covers `start_async`'s actual
+    // rejection case -- a `.timeout(...)` applied to the whole expression.
+    let timeout = context.timeout;
+    let right = right.clone();
+    let handle = handle.clone();
+    // The right side isn't spawned until the left side (process, input
+    // writer, and captures) has fully resolved, matching the synchronous
+    // `then`.
+    let combined: Box<Future<Item = ThenResult, Error = String> + Send> = Box::new(
+        left_out
+            .stage
+            .join3(left_stdout, left_stderr)
+            .map_err(|e| e.to_string())
+            .and_then(move |(left_result, left_stdout, left_stderr)| {
+                let right_out = match right.exec_async(context, &handle) {
+                    Ok(out) => out,
+                    Err(e) => return future::Either::A(future::err(e.to_string())),
+                };
+                let right_stdout = capture_or_empty(right_out.stdout_capture);
+                let right_stderr = capture_or_empty(right_out.stderr_capture);
+                future::Either::B(
+                    right_out
+                        .stage
+                        .join3(right_stdout, right_stderr)
+                        .map_err(|e| e.to_string())
+                        .map(move |(right_result, right_stdout, right_stderr)| {
+                            let mut stdout = left_stdout;
+                            stdout.extend(right_stdout);
+                            let mut stderr = left_stderr;
+                            stderr.extend(right_stderr);
+                            (combine(left_result, right_result), stdout, stderr)
+                        }),
+                )
+            }),
+    );
+    let combined = combined.shared();
+
+    let stage_view = combined.clone();
+    let stage: BoxStageFuture = Box::new(
+        stage_view
+            .map(|shared| shared.0)
+            .map_err(|e| ErrorKind::Msg((*e).clone()).into()),
+    );
+    let stdout_view = combined.clone();
+    let stdout_capture: BoxCaptureFuture = Box::new(
+        stdout_view
+            .map(|shared| shared.1.clone())
+            .map_err(|e| ErrorKind::Msg((*e).clone()).into()),
+    );
+    let stderr_capture: BoxCaptureFuture = Box::new(
+        combined
+            .map(|shared| shared.2.clone())
+            .map_err(|e| ErrorKind::Msg((*e).clone()).into()),
+    );
+
+    Ok(AsyncExecOutput {
+        stage: stage,
+        stdout_capture: Some(stdout_capture),
+        stderr_capture: Some(stderr_capture),
+        timeout: timeout,
+    })
+}
+
+fn exec_io_async(
+    io: &IoExpression,
+    inner: &Expression,
+    mut context: IoContext,
+    handle: &ReactorHandle,
+) -> Result<AsyncExecOutput> {
+    match *io {
+        IoExpression::Stdin(ref value) => {
+            context.stdin = value.try_clone()?;
+            inner.exec_async(context, handle)
+        }
+        IoExpression::Stdout(IoValue::Capture) => {
+            let (reader, writer) = pipe()?;
+            context.stdout = IoValue::Writer(writer);
+            let mut out = inner.exec_async(context, handle)?;
+            let capture: BoxCaptureFuture = Box::new(
+                read_to_end(reader, Vec::new())
+                    .map(|(_reader, bytes)| bytes)
+                    .map_err(Error::from),
+            );
+            out.stdout_capture = Some(capture);
+            Ok(out)
+        }
+        IoExpression::Stdout(ref value) => {
+            context.stdout = value.try_clone()?;
+            inner.exec_async(context, handle)
+        }
+        IoExpression::Stderr(IoValue::Capture) => {
+            let (reader, writer) = pipe()?;
+            context.stderr = IoValue::Writer(writer);
+            let mut out = inner.exec_async(context, handle)?;
+            let capture: BoxCaptureFuture = Box::new(
+                read_to_end(reader, Vec::new())
+                    .map(|(_reader, bytes)| bytes)
+                    .map_err(Error::from),
+            );
+            out.stderr_capture = Some(capture);
+            Ok(out)
+        }
+        IoExpression::Stderr(ref value) => {
+            context.stderr = value.try_clone()?;
+            inner.exec_async(context, handle)
+        }
+        IoExpression::StdoutToStderr => {
+            context.stdout = context.stderr.try_clone()?;
+            inner.exec_async(context, handle)
+        }
+        IoExpression::StderrToStdout => {
+            context.stderr = context.stdout.try_clone()?;
+            inner.exec_async(context, handle)
+        }
+        IoExpression::Dir(ref path) => {
+            context.dir = Some(path.clone());
+            inner.exec_async(context, handle)
+        }
+        IoExpression::Env(ref op) => {
+            context.env_ops.push(Arc::new(op.try_clone()));
+            inner.exec_async(context, handle)
+        }
+        IoExpression::Unchecked => {
+            // Mark the subtree's *result* unchecked after it runs, rather
+            // than threading an `unchecked` flag down through the context --
+            // see the comment on `IoContext` in lib.rs.
+            let mut out = inner.exec_async(context, handle)?;
+            out.stage = Box::new(out.stage.map(|mut result| {
+                result.checked = false;
+                result
+            }));
+            Ok(out)
+        }
+        IoExpression::Timeout(duration) => {
+            context.timeout = Some(duration);
+            inner.exec_async(context, handle)
+        }
+        #[cfg(all(unix, feature = "jobserver"))]
+        IoExpression::Jobserver(ref jobserver) => {
+            context.jobserver = Some(jobserver.clone());
+            inner.exec_async(context, handle)
+        }
+    }
+}
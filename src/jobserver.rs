@@ -0,0 +1,167 @@
+//! GNU Make-compatible jobserver integration, so a duct-driven process tree
+//! can participate in (and impose) `make -jN`-style concurrency limits. See
+//! <https://www.gnu.org/software/make/manual/html_node/Job-Slots.html> for
+//! the protocol implemented here. Gated behind the `jobserver` feature; it
+//! uses `libc` for the close-on-exec dance below.
+
+extern crate libc;
+
+use std::env;
+use std::ffi::OsString;
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::{Arc, Mutex, Once, ONCE_INIT};
+
+use os_pipe::{pipe, PipeReader, PipeWriter};
+
+/// A process-wide pool of job tokens, backed by an anonymous pipe preloaded
+/// with one byte per slot. Acquiring a slot is a blocking one-byte read;
+/// releasing (done automatically when a `JobserverToken` is dropped) writes
+/// the byte back. `Jobserver` is cheap to clone -- clones share the same
+/// underlying pipe.
+#[derive(Clone)]
+pub struct Jobserver(Arc<Inner>);
+
+struct Inner {
+    reader: Mutex<PipeReader>,
+    writer: Mutex<PipeWriter>,
+}
+
+impl Jobserver {
+    /// Create a brand new pool with `slots` tokens, preloading the pipe
+    /// with one byte per slot.
+    pub fn new(slots: usize) -> io::Result<Jobserver> {
+        let (reader, writer) = pipe()?;
+        (&writer).write_all(&vec![b'+'; slots])?;
+        Ok(Jobserver(Arc::new(Inner {
+            reader: Mutex::new(reader),
+            writer: Mutex::new(writer),
+        })))
+    }
+
+    /// Reconstruct the pool a parent `make -jN` already created, by parsing
+    /// `--jobserver-auth=R,W` (or the older `--jobserver-fds=R,W`) out of
+    /// the inherited `MAKEFLAGS`. Returns `None` if we're not running under
+    /// a jobserver-enabled `make` at all, so callers can fall back to
+    /// running unconstrained.
+    pub fn from_env() -> Option<Jobserver> {
+        let makeflags = env::var("MAKEFLAGS").ok()?;
+        for arg in makeflags.split_whitespace() {
+            if let Some(auth) = jobserver_auth(arg) {
+                let mut parts = auth.splitn(2, ',');
+                let read_fd: RawFd = parts.next()?.parse().ok()?;
+                let write_fd: RawFd = parts.next()?.parse().ok()?;
+                // These fds are only valid because our parent handed them
+                // down to us already open and inheritable; we're not
+                // opening anything new here.
+                let jobserver = unsafe {
+                    Jobserver(Arc::new(Inner {
+                        reader: Mutex::new(PipeReader::from_raw_fd(read_fd)),
+                        writer: Mutex::new(PipeWriter::from_raw_fd(write_fd)),
+                    }))
+                };
+                return Some(jobserver);
+            }
+        }
+        None
+    }
+
+    /// Block until a token is available, and return a guard that writes it
+    /// back to the pool when dropped.
+    pub(crate) fn acquire(&self) -> io::Result<JobserverToken> {
+        let mut byte = [0u8; 1];
+        self.0.reader.lock().unwrap().read_exact(&mut byte)?;
+        Ok(JobserverToken {
+            jobserver: self.clone(),
+        })
+    }
+
+    /// The `MAKEFLAGS` value to export to a child so that it (and anything
+    /// it spawns in turn) can join this same pool.
+    pub(crate) fn makeflags(&self) -> OsString {
+        let reader_fd = self.0.reader.lock().unwrap().as_raw_fd();
+        let writer_fd = self.0.writer.lock().unwrap().as_raw_fd();
+        format!(" -j --jobserver-auth={},{}", reader_fd, writer_fd).into()
+    }
+
+    /// Clear close-on-exec on this pool's read/write fds, but only inside
+    /// the child `command` is about to become -- installed as a `pre_exec`
+    /// hook, it runs after `fork` and before `exec`, so it never affects
+    /// the fds as seen by any other child, or by us.
+    pub(crate) fn inherit_for_spawn(&self, command: &mut Command) {
+        let reader_fd = self.0.reader.lock().unwrap().as_raw_fd();
+        let writer_fd = self.0.writer.lock().unwrap().as_raw_fd();
+        unsafe {
+            command.pre_exec(move || {
+                clear_cloexec(reader_fd)?;
+                clear_cloexec(writer_fd)?;
+                Ok(())
+            });
+        }
+    }
+}
+
+fn clear_cloexec(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn jobserver_auth(arg: &str) -> Option<&str> {
+    for prefix in &["--jobserver-auth=", "--jobserver-fds="] {
+        if arg.starts_with(prefix) {
+            return Some(&arg[prefix.len()..]);
+        }
+    }
+    None
+}
+
+/// A single acquired job slot, released back to the pool when dropped. The
+/// implicit token our own process holds (the one a real `make -jN` never
+/// puts in the pipe) is never represented by one of these, so it's never at
+/// risk of being written back.
+pub(crate) struct JobserverToken {
+    jobserver: Jobserver,
+}
+
+impl Drop for JobserverToken {
+    fn drop(&mut self) {
+        // Best-effort: there's nothing more to do with a failed write from
+        // inside a destructor.
+        let _ = self.jobserver.0.writer.lock().unwrap().write_all(&[b'+']);
+    }
+}
+
+static INIT_DEFAULT: Once = ONCE_INIT;
+static mut DEFAULT_JOBSERVER: Option<Jobserver> = None;
+
+/// The process-wide default jobserver, used by any expression that doesn't
+/// call `.jobserver(...)` itself. Lazily initialized from the inherited
+/// `MAKEFLAGS`, if any; otherwise there is no default, and expressions
+/// spawn without any concurrency limit.
+pub fn default_jobserver() -> Option<Jobserver> {
+    unsafe {
+        INIT_DEFAULT.call_once(|| {
+            DEFAULT_JOBSERVER = Jobserver::from_env();
+        });
+        DEFAULT_JOBSERVER.clone()
+    }
+}
+
+/// Override the process-wide default jobserver (see `default_jobserver`).
+pub fn set_default_jobserver(jobserver: Jobserver) {
+    unsafe {
+        INIT_DEFAULT.call_once(|| {});
+        DEFAULT_JOBSERVER = Some(jobserver);
+    }
+}
@@ -0,0 +1,1169 @@
+//! Duct is a library for running child processes and pipelines of child
+//! processes. It wants to make the common cases as readable as possible,
+//! while still giving you precise control over configuration details like
+//! environment variables, current directory, and whether a given stage is
+//! allowed to fail.
+//!
+//! An `Expression` is a tree of commands, built up with combinators like
+//! `pipe` and `then`, plus whatever IO configuration you layer on with
+//! builder methods. Expressions are cheap to clone and immutable, so the
+//! same expression can be `run()` many times. Nothing actually happens
+//! until you call `run()`, `read()`, or `start()`.
+
+extern crate os_pipe;
+#[macro_use]
+extern crate error_chain;
+#[cfg(unix)]
+extern crate libc;
+
+use os_pipe::{pipe, IntoStdio, PipeReader, PipeWriter};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::os::unix::process::ExitStatusExt;
+
+error_chain! {
+    foreign_links {
+        Io(io::Error);
+    }
+    errors {
+        /// Returned when a checked expression exits with a non-zero status.
+        Status(output: Output) {
+            description("command exited with a non-zero status")
+            display("command exited with non-zero status: {:?}", output.status)
+        }
+        /// Returned when a `.timeout(...)` deadline elapses before the
+        /// expression finishes. `output` carries whatever captured output
+        /// was collected before the expression was killed.
+        Timeout(output: Output) {
+            description("command timed out")
+            display("command timed out: {:?}", output.status)
+        }
+    }
+}
+
+/// Build a `Cmd` expression out of a program name and its arguments, without
+/// going through a shell. The program is looked up on `PATH` the same way
+/// `std::process::Command` does. Every argument type just needs to convert
+/// to an `OsString`.
+#[macro_export]
+macro_rules! cmd {
+    ( $($arg:expr),* $(,)* ) => {
+        {
+            let args: Vec<::std::ffi::OsString> = vec![$( ::std::convert::Into::into($arg) ),*];
+            $crate::Expression::new_cmd(args)
+        }
+    };
+}
+
+/// Run a command line through the platform shell (`sh -c` on Unix, `cmd /C`
+/// on Windows).
+pub fn sh<T: Into<OsString>>(command: T) -> Expression {
+    Expression::new_sh(command.into())
+}
+
+/// Swallow broken pipe errors, and pass through everything else. Input
+/// writer threads hit this whenever the child on the other end of the pipe
+/// exits before we're done writing, which is a normal race, not a bug.
+pub(crate) fn suppress_broken_pipe_errors(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        other => other,
+    }
+}
+
+/// Returns the Unix signal that killed this process, if it was killed by a
+/// signal rather than exiting normally. This is a thin wrapper around
+/// `ExitStatusExt::signal`, so callers who already have an `Output` in hand
+/// can distinguish "exited with code N" from "killed by signal M" without
+/// pulling in the extension trait themselves.
+#[cfg(unix)]
+pub fn signal(output: &Output) -> Option<i32> {
+    output.status.signal()
+}
+
+/// A tree of commands, together with whatever IO configuration (pipes,
+/// redirects, environment, directory, checked-ness) has been layered on top
+/// of it. Cloning an `Expression` is cheap; it's just bumping a reference
+/// count.
+#[derive(Clone)]
+pub struct Expression(Arc<ExpressionInner>);
+
+enum ExpressionInner {
+    Cmd(Vec<OsString>),
+    Sh(OsString),
+    Pipe(Expression, Expression),
+    Then(Expression, Expression),
+    Io(IoExpression, Expression),
+}
+
+enum IoExpression {
+    Stdin(IoValue),
+    Stdout(IoValue),
+    Stderr(IoValue),
+    StdoutToStderr,
+    StderrToStdout,
+    Dir(PathBuf),
+    Env(EnvOp),
+    Unchecked,
+    Timeout(Duration),
+    #[cfg(all(unix, feature = "jobserver"))]
+    Jobserver(jobserver::Jobserver),
+}
+
+/// A deferred environment-variable operation. These are collected, in the
+/// order the expression was built, and replayed at spawn time. See
+/// `resolve_env` for the precedence rules.
+enum EnvOp {
+    Set(OsString, OsString),
+    Remove(OsString),
+    Clear,
+    Full(Arc<HashMap<OsString, OsString>>),
+}
+
+/// A value that an IO stream can be hooked up to. Constructed eagerly by
+/// the builder methods, and resolved to a real `Stdio` at spawn time (or,
+/// for `Capture`, expanded into a pipe and a draining thread as soon as
+/// it's encountered).
+enum IoValue {
+    ParentIo,
+    Null,
+    Path(PathBuf),
+    File(File),
+    Reader(PipeReader),
+    Writer(PipeWriter),
+    Input(Arc<Vec<u8>>),
+    Capture,
+}
+
+impl IoValue {
+    pub(crate) fn try_clone(&self) -> io::Result<IoValue> {
+        Ok(match *self {
+            IoValue::ParentIo => IoValue::ParentIo,
+            IoValue::Null => IoValue::Null,
+            IoValue::Path(ref p) => IoValue::Path(p.clone()),
+            IoValue::File(ref f) => IoValue::File(f.try_clone()?),
+            IoValue::Reader(ref r) => IoValue::Reader(r.try_clone()?),
+            IoValue::Writer(ref w) => IoValue::Writer(w.try_clone()?),
+            IoValue::Input(ref v) => IoValue::Input(v.clone()),
+            IoValue::Capture => IoValue::Capture,
+        })
+    }
+
+    fn into_input_stdio(self) -> io::Result<(Stdio, Option<Arc<Vec<u8>>>)> {
+        Ok(match self {
+            IoValue::ParentIo => (Stdio::inherit(), None),
+            IoValue::Null => (Stdio::null(), None),
+            IoValue::Path(p) => (Stdio::from(File::open(p)?), None),
+            IoValue::File(f) => (Stdio::from(f), None),
+            IoValue::Reader(r) => (r.into_stdio(), None),
+            IoValue::Writer(_) => unreachable!("a pipe writer can't be used as stdin"),
+            IoValue::Input(bytes) => (Stdio::piped(), Some(bytes)),
+            IoValue::Capture => unreachable!("stdin can't be captured"),
+        })
+    }
+
+    fn into_output_stdio(self) -> io::Result<Stdio> {
+        Ok(match self {
+            IoValue::ParentIo => Stdio::inherit(),
+            IoValue::Null => Stdio::null(),
+            IoValue::Path(p) => Stdio::from(File::create(p)?),
+            IoValue::File(f) => Stdio::from(f),
+            IoValue::Writer(w) => w.into_stdio(),
+            IoValue::Reader(_) => unreachable!("a pipe reader can't be used as stdout/stderr"),
+            IoValue::Input(_) => unreachable!("input is only valid for stdin"),
+            IoValue::Capture => unreachable!("capture must be expanded before reaching spawn"),
+        })
+    }
+}
+
+/// State threaded top-down through the expression tree while we spawn it.
+/// Each `Io` node mutates a clone of the context on its way down to its
+/// inner expression; `Then` hands the same incoming context to both of its
+/// children, and `Pipe` does too, except that the left child's stdout and
+/// the right child's stdin are replaced by the two ends of a new pipe.
+#[derive(Clone)]
+struct IoContext {
+    stdin: IoValue,
+    stdout: IoValue,
+    stderr: IoValue,
+    dir: Option<PathBuf>,
+    env_ops: Vec<Arc<EnvOp>>,
+    // The most recently applied `.timeout(...)`, if any. There's no
+    // per-stage precedence rule here (unlike `env`): a timeout is enforced
+    // once, from the top, by a single watchdog spawned in `start()`. See
+    // the comment on `ExecOutput::timeout`.
+    //
+    // Note that `.unchecked()` is *not* a context field, unlike the other
+    // builder-driven settings here: threading it top-down the same way
+    // would clobber every leaf underneath it with the same checked-ness,
+    // which breaks `combine()`'s "checked failure on the left beats
+    // unchecked on the right" precedence for anything nested inside an
+    // outer `.unchecked()`. Instead each leaf keeps its own checked-ness,
+    // and `.unchecked()` marks the *result* of its subtree as unchecked
+    // after running it -- see `HandleInner::Unchecked`.
+    timeout: Option<Duration>,
+    #[cfg(all(unix, feature = "jobserver"))]
+    jobserver: Option<jobserver::Jobserver>,
+    // Shared (via `Arc`, never re-created) across every node in one
+    // `start()`/`run()` call's tree, including both sides of `pipe`/`then`.
+    // The real `make` jobserver protocol gives the invoking process one
+    // implicit token it never has to acquire or write back; we model that
+    // by letting exactly one leaf in the tree skip `Jobserver::acquire()`.
+    // Without this, an expression whose concurrent leaf count exceeds the
+    // pool size (e.g. a `pipe` under a single-slot pool) deadlocks: every
+    // leaf blocks acquiring a token before any of them can be reaped to
+    // release one.
+    #[cfg(all(unix, feature = "jobserver"))]
+    jobserver_implicit_slot: Arc<AtomicBool>,
+}
+
+impl IoContext {
+    fn root() -> IoContext {
+        IoContext {
+            stdin: IoValue::ParentIo,
+            stdout: IoValue::ParentIo,
+            stderr: IoValue::ParentIo,
+            dir: None,
+            env_ops: Vec::new(),
+            timeout: None,
+            #[cfg(all(unix, feature = "jobserver"))]
+            jobserver: None,
+            #[cfg(all(unix, feature = "jobserver"))]
+            jobserver_implicit_slot: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<IoContext> {
+        Ok(IoContext {
+            stdin: self.stdin.try_clone()?,
+            stdout: self.stdout.try_clone()?,
+            stderr: self.stderr.try_clone()?,
+            dir: self.dir.clone(),
+            env_ops: self.env_ops.clone(),
+            timeout: self.timeout,
+            #[cfg(all(unix, feature = "jobserver"))]
+            jobserver: self.jobserver.clone(),
+            #[cfg(all(unix, feature = "jobserver"))]
+            jobserver_implicit_slot: self.jobserver_implicit_slot.clone(),
+        })
+    }
+}
+
+/// Replay the env ops in the order the expression was *built* (innermost,
+/// i.e. earliest call, first), starting from the inherited environment.
+/// `env`/`env_remove`/`env_clear` calls each apply on top of whatever came
+/// before, so a later (outer) call wins over an earlier (inner) one. A
+/// `full_env` call is the exception: it replaces the environment outright,
+/// and any later call is ignored until another `full_env` replaces it
+/// again. This is what lets `full_env` suppress an outer `env` call.
+fn resolve_env(ops: &[Arc<EnvOp>]) -> HashMap<OsString, OsString> {
+    let mut env: HashMap<OsString, OsString> = std::env::vars_os().collect();
+    let mut locked = false;
+    // `ops` was built by appending as we walked down from the outermost
+    // call to the innermost one, so it's in reverse chronological order.
+    for op in ops.iter().rev() {
+        if locked {
+            if let EnvOp::Full(ref m) = **op {
+                env = (**m).clone();
+            }
+            continue;
+        }
+        match **op {
+            EnvOp::Set(ref k, ref v) => {
+                env.insert(k.clone(), v.clone());
+            }
+            EnvOp::Remove(ref k) => {
+                env.remove(k);
+            }
+            EnvOp::Clear => {
+                env.clear();
+            }
+            EnvOp::Full(ref m) => {
+                env = (**m).clone();
+                locked = true;
+            }
+        }
+    }
+    env
+}
+
+impl Expression {
+    #[doc(hidden)]
+    pub fn new_cmd(argv: Vec<OsString>) -> Expression {
+        Expression(Arc::new(ExpressionInner::Cmd(argv)))
+    }
+
+    fn new_sh(command: OsString) -> Expression {
+        Expression(Arc::new(ExpressionInner::Sh(command)))
+    }
+
+    fn io(&self, io: IoExpression) -> Expression {
+        Expression(Arc::new(ExpressionInner::Io(io, self.clone())))
+    }
+
+    /// Run `self`, and pipe its stdout into `right`'s stdin.
+    pub fn pipe(&self, right: Expression) -> Expression {
+        Expression(Arc::new(ExpressionInner::Pipe(self.clone(), right)))
+    }
+
+    /// Run `self`, and then (regardless of whether it succeeded) run
+    /// `right`.
+    pub fn then(&self, right: Expression) -> Expression {
+        Expression(Arc::new(ExpressionInner::Then(self.clone(), right)))
+    }
+
+    /// Don't let a non-zero exit status from this expression turn into an
+    /// error. The exit code is still reported in the `Output`.
+    pub fn unchecked(&self) -> Expression {
+        self.io(IoExpression::Unchecked)
+    }
+
+    /// Write `input` to the child's stdin.
+    pub fn input<T: Into<Vec<u8>>>(&self, input: T) -> Expression {
+        self.io(IoExpression::Stdin(IoValue::Input(Arc::new(input.into()))))
+    }
+
+    /// Read the child's stdin from a file at `path`.
+    pub fn stdin<P: AsRef<Path>>(&self, path: P) -> Expression {
+        self.io(IoExpression::Stdin(IoValue::Path(path.as_ref().to_owned())))
+    }
+
+    /// Read the child's stdin from an already-open file.
+    pub fn stdin_file(&self, file: File) -> Expression {
+        self.io(IoExpression::Stdin(IoValue::File(file)))
+    }
+
+    /// Give the child's stdin `/dev/null` (or the Windows equivalent).
+    pub fn stdin_null(&self) -> Expression {
+        self.io(IoExpression::Stdin(IoValue::Null))
+    }
+
+    /// Write the child's stdout to a file at `path`, creating it if
+    /// necessary.
+    pub fn stdout<P: AsRef<Path>>(&self, path: P) -> Expression {
+        self.io(IoExpression::Stdout(IoValue::Path(path.as_ref().to_owned())))
+    }
+
+    /// Write the child's stdout to an already-open file.
+    pub fn stdout_file(&self, file: File) -> Expression {
+        self.io(IoExpression::Stdout(IoValue::File(file)))
+    }
+
+    /// Send the child's stdout to `/dev/null`.
+    pub fn stdout_null(&self) -> Expression {
+        self.io(IoExpression::Stdout(IoValue::Null))
+    }
+
+    /// Capture the child's stdout, making it available on `Output::stdout`.
+    pub fn stdout_capture(&self) -> Expression {
+        self.io(IoExpression::Stdout(IoValue::Capture))
+    }
+
+    /// Redirect the child's stdout to wherever stderr is currently going.
+    pub fn stdout_to_stderr(&self) -> Expression {
+        self.io(IoExpression::StdoutToStderr)
+    }
+
+    /// Write the child's stderr to a file at `path`, creating it if
+    /// necessary.
+    pub fn stderr<P: AsRef<Path>>(&self, path: P) -> Expression {
+        self.io(IoExpression::Stderr(IoValue::Path(path.as_ref().to_owned())))
+    }
+
+    /// Write the child's stderr to an already-open file.
+    pub fn stderr_file(&self, file: File) -> Expression {
+        self.io(IoExpression::Stderr(IoValue::File(file)))
+    }
+
+    /// Send the child's stderr to `/dev/null`.
+    pub fn stderr_null(&self) -> Expression {
+        self.io(IoExpression::Stderr(IoValue::Null))
+    }
+
+    /// Capture the child's stderr, making it available on `Output::stderr`.
+    pub fn stderr_capture(&self) -> Expression {
+        self.io(IoExpression::Stderr(IoValue::Capture))
+    }
+
+    /// Redirect the child's stderr to wherever stdout is currently going.
+    pub fn stderr_to_stdout(&self) -> Expression {
+        self.io(IoExpression::StderrToStdout)
+    }
+
+    /// Set the working directory the child is spawned in.
+    pub fn dir<P: AsRef<Path>>(&self, path: P) -> Expression {
+        self.io(IoExpression::Dir(path.as_ref().to_owned()))
+    }
+
+    /// Set an environment variable, inherited alongside the parent's
+    /// environment (unless something clears or replaces it).
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&self, name: K, val: V) -> Expression {
+        let op = EnvOp::Set(name.as_ref().to_owned(), val.as_ref().to_owned());
+        self.io(IoExpression::Env(op))
+    }
+
+    /// Remove an environment variable, whether it was inherited from the
+    /// parent or set by an earlier (inner) `env` call. Like `env`, this
+    /// layers in call order with the rest of the env overrides: a later
+    /// (outer) `env` call for the same name puts it back.
+    pub fn env_remove<K: AsRef<OsStr>>(&self, name: K) -> Expression {
+        let op = EnvOp::Remove(name.as_ref().to_owned());
+        self.io(IoExpression::Env(op))
+    }
+
+    /// Drop every inherited environment variable, leaving the child with
+    /// none except whatever `env` calls come after this one in the chain.
+    /// Unlike `full_env`, a later `env_clear`/`env_remove`/`env` still
+    /// layers normally on top of this instead of being suppressed by it.
+    pub fn env_clear(&self) -> Expression {
+        self.io(IoExpression::Env(EnvOp::Clear))
+    }
+
+    /// Replace the entire environment the child sees. Unlike `env`, this is
+    /// absolute: any other `env`/`env_remove`/`env_clear` call is ignored,
+    /// no matter whether it was chained before or after this one.
+    pub fn full_env<K, V, I>(&self, vars: I) -> Expression
+    where
+        K: Into<OsString>,
+        V: Into<OsString>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let map = vars
+            .into_iter()
+            .map(|(k, v)| (k.into(), v.into()))
+            .collect();
+        self.io(IoExpression::Env(EnvOp::Full(Arc::new(map))))
+    }
+
+    /// Kill the whole expression if it hasn't finished within `duration`.
+    /// This is enforced from the top: `start()` spawns a single watchdog
+    /// thread that sleeps for `duration` and then calls the same recursive
+    /// `Handle::kill()` used elsewhere, rather than racing a `wait` against
+    /// a timer on each leaf. If the deadline fires, `run()`/`read()`/`wait()`
+    /// return `ErrorKind::Timeout` instead of `ErrorKind::Status`, carrying
+    /// whatever output had been captured so far.
+    pub fn timeout(&self, duration: Duration) -> Expression {
+        self.io(IoExpression::Timeout(duration))
+    }
+
+    /// Use `jobserver` to limit how many of this expression's leaf
+    /// commands (across the whole tree, including both sides of `pipe`)
+    /// can be spawned at once, and export it to each child via `MAKEFLAGS`
+    /// so that nested, jobserver-aware builds honor the same limit. This
+    /// overrides `jobserver::default_jobserver()` for this expression.
+    #[cfg(all(unix, feature = "jobserver"))]
+    pub fn jobserver(&self, jobserver: jobserver::Jobserver) -> Expression {
+        self.io(IoExpression::Jobserver(jobserver))
+    }
+
+    /// Run `self`, blocking the current thread until the expression exits
+    /// and checking its status.
+    pub fn run(&self) -> Result<Output> {
+        self.start()?.wait()
+    }
+
+    /// Run `self` and capture just its stdout as a `String`, trimming a
+    /// single trailing newline the way command substitution in a shell
+    /// does.
+    pub fn read(&self) -> Result<String> {
+        let output = self.stdout_capture().run()?;
+        let mut stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        if stdout.ends_with('\n') {
+            stdout.truncate(stdout.len() - 1);
+        }
+        if stdout.ends_with('\r') {
+            stdout.truncate(stdout.len() - 1);
+        }
+        Ok(stdout)
+    }
+
+    /// Start running `self` in the background, returning a `Handle` that
+    /// can be waited on, polled, or killed.
+    pub fn start(&self) -> Result<Handle> {
+        let exec_output = self.exec(IoContext::root())?;
+        let inner = Arc::new(exec_output.handle);
+        let timed_out = Arc::new(AtomicBool::new(false));
+        if let Some(duration) = exec_output.timeout {
+            let watchdog_inner = inner.clone();
+            let watchdog_timed_out = timed_out.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(duration);
+                // If the tree already finished on its own, leave it alone.
+                // Use `still_running` rather than `try_wait` here: `wait`
+                // holds a lock on each child for as long as it runs, and
+                // `try_wait` blocks taking that same lock, which would let
+                // a concurrent blocking `wait()` call starve the watchdog
+                // for the child's entire lifetime and defeat the deadline.
+                if watchdog_inner.still_running() {
+                    watchdog_timed_out.store(true, Ordering::SeqCst);
+                    let _ = watchdog_inner.kill();
+                }
+            });
+        }
+        Ok(Handle {
+            inner: inner,
+            timed_out: timed_out,
+            stdout_capture: Mutex::new(Capture::new(exec_output.stdout_capture)),
+            stderr_capture: Mutex::new(Capture::new(exec_output.stderr_capture)),
+        })
+    }
+
+    fn exec(&self, context: IoContext) -> io::Result<ExecOutput> {
+        match *self.0 {
+            ExpressionInner::Cmd(ref argv) => exec_argv(argv, context),
+            ExpressionInner::Sh(ref command) => exec_sh(command, context),
+            ExpressionInner::Pipe(ref left, ref right) => exec_pipe(left, right, context),
+            ExpressionInner::Then(ref left, ref right) => exec_then(left, right, context),
+            ExpressionInner::Io(ref io, ref inner) => exec_io(io, inner, context),
+        }
+    }
+}
+
+/// The result of spawning an expression: the (possibly still running) tree
+/// of child processes, plus the background threads draining any captured
+/// output, if a `stdout_capture`/`stderr_capture` was present anywhere in
+/// the expression.
+struct ExecOutput {
+    handle: HandleInner,
+    stdout_capture: Option<JoinHandle<io::Result<Vec<u8>>>>,
+    stderr_capture: Option<JoinHandle<io::Result<Vec<u8>>>>,
+    // The `.timeout(...)` in effect for this subtree, if any, bubbled up so
+    // that `start()` (the only place that actually knows it's looking at
+    // the root of the whole expression) can spawn the watchdog.
+    timeout: Option<Duration>,
+}
+
+fn exec_argv(argv: &[OsString], context: IoContext) -> io::Result<ExecOutput> {
+    let mut command = Command::new(&argv[0]);
+    command.args(&argv[1..]);
+    if let Some(ref dir) = context.dir {
+        command.current_dir(dir);
+    }
+    command.env_clear();
+    #[allow(unused_mut)]
+    let mut env = resolve_env(&context.env_ops);
+
+    #[cfg(all(unix, feature = "jobserver"))]
+    let effective_jobserver = context.jobserver.clone().or_else(jobserver::default_jobserver);
+    #[cfg(all(unix, feature = "jobserver"))]
+    {
+        if let Some(ref js) = effective_jobserver {
+            env.insert(OsString::from("MAKEFLAGS"), js.makeflags());
+        }
+    }
+    command.envs(env);
+
+    let (stdin_stdio, input_bytes) = context.stdin.into_input_stdio()?;
+    command.stdin(stdin_stdio);
+    command.stdout(context.stdout.into_output_stdio()?);
+    command.stderr(context.stderr.into_output_stdio()?);
+
+    // Acquire a token before spawning, blocking if the pool is exhausted,
+    // and make the pool's fds inheritable for this one spawn so the child
+    // can actually open the fds named in the `MAKEFLAGS` we just set. The
+    // first leaf anywhere in this tree to reach this point takes the
+    // implicit slot instead of acquiring, so a self-contained expression
+    // (e.g. a `pipe` with more concurrent leaves than the pool has tokens)
+    // can never deadlock waiting on itself.
+    #[cfg(all(unix, feature = "jobserver"))]
+    let jobserver_token = match effective_jobserver {
+        Some(ref js) => {
+            js.inherit_for_spawn(&mut command);
+            if context.jobserver_implicit_slot.swap(false, Ordering::SeqCst) {
+                None
+            } else {
+                Some(js.acquire()?)
+            }
+        }
+        None => None,
+    };
+
+    let mut child = command.spawn()?;
+    #[cfg(unix)]
+    let pid = child.id() as libc::pid_t;
+    let input_thread = input_bytes.map(|bytes| {
+        let mut stdin_pipe = child.stdin.take().expect("stdin was piped for input()");
+        std::thread::spawn(move || suppress_broken_pipe_errors(stdin_pipe.write_all(&bytes)))
+    });
+
+    Ok(ExecOutput {
+        handle: HandleInner::Cmd(CmdHandle {
+            child: Mutex::new(child),
+            #[cfg(unix)]
+            pid: pid,
+            // Every leaf is checked by default; `.unchecked()` marks its
+            // subtree's *result* as unchecked after the fact instead (see
+            // `HandleInner::Unchecked`), so it never reaches down here.
+            checked: true,
+            input_thread: Mutex::new(input_thread),
+            #[cfg(all(unix, feature = "jobserver"))]
+            jobserver_token: Mutex::new(jobserver_token),
+        }),
+        stdout_capture: None,
+        stderr_capture: None,
+        timeout: context.timeout,
+    })
+}
+
+#[cfg(unix)]
+fn exec_sh(command: &OsStr, context: IoContext) -> io::Result<ExecOutput> {
+    let argv = vec![OsString::from("sh"), OsString::from("-c"), command.to_owned()];
+    exec_argv(&argv, context)
+}
+
+#[cfg(windows)]
+fn exec_sh(command: &OsStr, context: IoContext) -> io::Result<ExecOutput> {
+    let argv = vec![OsString::from("cmd"), OsString::from("/C"), command.to_owned()];
+    exec_argv(&argv, context)
+}
+
+fn exec_pipe(left: &Expression, right: &Expression, context: IoContext) -> io::Result<ExecOutput> {
+    let (reader, writer) = pipe()?;
+    let left_context = IoContext {
+        stdin: context.stdin.try_clone()?,
+        stdout: IoValue::Writer(writer),
+        stderr: context.stderr.try_clone()?,
+        dir: context.dir.clone(),
+        env_ops: context.env_ops.clone(),
+        timeout: context.timeout,
+        #[cfg(all(unix, feature = "jobserver"))]
+        jobserver: context.jobserver.clone(),
+        #[cfg(all(unix, feature = "jobserver"))]
+        jobserver_implicit_slot: context.jobserver_implicit_slot.clone(),
+    };
+    let right_context = IoContext {
+        stdin: IoValue::Reader(reader),
+        stdout: context.stdout.try_clone()?,
+        stderr: context.stderr.try_clone()?,
+        dir: context.dir.clone(),
+        env_ops: context.env_ops.clone(),
+        timeout: context.timeout,
+        #[cfg(all(unix, feature = "jobserver"))]
+        jobserver: context.jobserver.clone(),
+        #[cfg(all(unix, feature = "jobserver"))]
+        jobserver_implicit_slot: context.jobserver_implicit_slot.clone(),
+    };
+    let left_out = left.exec(left_context)?;
+    let right_out = right.exec(right_context)?;
+    Ok(ExecOutput {
+        handle: HandleInner::Pipe(Box::new(left_out.handle), Box::new(right_out.handle)),
+        stdout_capture: left_out.stdout_capture.or(right_out.stdout_capture),
+        stderr_capture: left_out.stderr_capture.or(right_out.stderr_capture),
+        timeout: left_out.timeout.or(right_out.timeout),
+    })
+}
+
+fn exec_then(left: &Expression, right: &Expression, context: IoContext) -> io::Result<ExecOutput> {
+    // `then` stages are strictly sequential, so we run the left side to
+    // completion here, and only the right side is left running when this
+    // function returns.
+    let left_context = context.try_clone()?;
+    let left_out = left.exec(left_context)?;
+    let (left_result, left_stdout, left_stderr) = finish(left_out)?;
+
+    let right_out = right.exec(context)?;
+    Ok(ExecOutput {
+        handle: HandleInner::Then {
+            left: left_result,
+            right: Box::new(right_out.handle),
+        },
+        stdout_capture: join_captured(left_stdout, right_out.stdout_capture),
+        stderr_capture: join_captured(left_stderr, right_out.stderr_capture),
+        timeout: right_out.timeout,
+    })
+}
+
+/// Combine bytes we've already collected from a finished left-hand stage
+/// with a still-running capture thread from the right-hand stage, so the
+/// final result reflects both stages writing to the same captured stream.
+fn join_captured(
+    left_bytes: Option<Vec<u8>>,
+    right_thread: Option<JoinHandle<io::Result<Vec<u8>>>>,
+) -> Option<JoinHandle<io::Result<Vec<u8>>>> {
+    match (left_bytes, right_thread) {
+        (None, right) => right,
+        (Some(left), None) => Some(std::thread::spawn(move || Ok(left))),
+        (Some(mut left), Some(right)) => Some(std::thread::spawn(move || {
+            let mut combined = right.join().unwrap_or(Ok(Vec::new()))?;
+            left.append(&mut combined);
+            Ok(left)
+        })),
+    }
+}
+
+/// Wait out an already-started subtree to completion, recursively, and
+/// return its combined status alongside any bytes its own capture threads
+/// produced. Used to synchronously resolve the left side of a `then`.
+fn finish(
+    exec_output: ExecOutput,
+) -> io::Result<(StageResult, Option<Vec<u8>>, Option<Vec<u8>>)> {
+    let result = exec_output.handle.wait()?;
+    let stdout = match exec_output.stdout_capture {
+        Some(thread) => Some(thread.join().unwrap_or(Ok(Vec::new()))?),
+        None => None,
+    };
+    let stderr = match exec_output.stderr_capture {
+        Some(thread) => Some(thread.join().unwrap_or(Ok(Vec::new()))?),
+        None => None,
+    };
+    Ok((result, stdout, stderr))
+}
+
+fn exec_io(io: &IoExpression, inner: &Expression, mut context: IoContext) -> io::Result<ExecOutput> {
+    match *io {
+        IoExpression::Stdin(ref value) => {
+            context.stdin = value.try_clone()?;
+            inner.exec(context)
+        }
+        IoExpression::Stdout(IoValue::Capture) => {
+            let (reader, writer) = pipe()?;
+            context.stdout = IoValue::Writer(writer);
+            let thread = std::thread::spawn(move || read_capture(reader));
+            let mut out = inner.exec(context)?;
+            out.stdout_capture = Some(thread);
+            Ok(out)
+        }
+        IoExpression::Stdout(ref value) => {
+            context.stdout = value.try_clone()?;
+            inner.exec(context)
+        }
+        IoExpression::Stderr(IoValue::Capture) => {
+            let (reader, writer) = pipe()?;
+            context.stderr = IoValue::Writer(writer);
+            let thread = std::thread::spawn(move || read_capture(reader));
+            let mut out = inner.exec(context)?;
+            out.stderr_capture = Some(thread);
+            Ok(out)
+        }
+        IoExpression::Stderr(ref value) => {
+            context.stderr = value.try_clone()?;
+            inner.exec(context)
+        }
+        IoExpression::StdoutToStderr => {
+            context.stdout = context.stderr.try_clone()?;
+            inner.exec(context)
+        }
+        IoExpression::StderrToStdout => {
+            context.stderr = context.stdout.try_clone()?;
+            inner.exec(context)
+        }
+        IoExpression::Dir(ref path) => {
+            context.dir = Some(path.clone());
+            inner.exec(context)
+        }
+        IoExpression::Env(ref op) => {
+            context.env_ops.push(Arc::new(op.try_clone()));
+            inner.exec(context)
+        }
+        IoExpression::Unchecked => {
+            let mut out = inner.exec(context)?;
+            out.handle = HandleInner::Unchecked(Box::new(out.handle));
+            Ok(out)
+        }
+        IoExpression::Timeout(duration) => {
+            context.timeout = Some(duration);
+            inner.exec(context)
+        }
+        #[cfg(all(unix, feature = "jobserver"))]
+        IoExpression::Jobserver(ref jobserver) => {
+            context.jobserver = Some(jobserver.clone());
+            inner.exec(context)
+        }
+    }
+}
+
+impl EnvOp {
+    fn try_clone(&self) -> EnvOp {
+        match *self {
+            EnvOp::Set(ref k, ref v) => EnvOp::Set(k.clone(), v.clone()),
+            EnvOp::Remove(ref k) => EnvOp::Remove(k.clone()),
+            EnvOp::Clear => EnvOp::Clear,
+            EnvOp::Full(ref m) => EnvOp::Full(m.clone()),
+        }
+    }
+}
+
+fn read_capture(mut reader: PipeReader) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// The combined exit status of a (possibly compound) expression, along
+/// with whether that status should actually be checked for success.
+#[derive(Clone, Copy)]
+struct StageResult {
+    status: ExitStatus,
+    checked: bool,
+}
+
+impl StageResult {
+    fn success(&self) -> bool {
+        self.status.success()
+    }
+}
+
+/// Combine the results of two sequential or piped stages. The right side
+/// wins whenever it failed, *unless* it was unchecked and the left side was
+/// a checked failure, in which case the left side's failure takes
+/// precedence. If the right side succeeded, the left side's result (status
+/// and checked-ness) is what's reported, matching the classic
+/// pipefail-style "report the rightmost failure, or else the leftmost
+/// status" rule.
+fn combine(left: StageResult, right: StageResult) -> StageResult {
+    if !right.success() {
+        if !right.checked && left.checked && !left.success() {
+            left
+        } else {
+            right
+        }
+    } else {
+        left
+    }
+}
+
+/// A single spawned child process, plus whatever writer thread is feeding
+/// it input.
+struct CmdHandle {
+    child: Mutex<Child>,
+    // The child's PID, captured at spawn time so `kill` can signal it
+    // directly instead of locking `child` -- see the comment on `kill`.
+    #[cfg(unix)]
+    pid: libc::pid_t,
+    checked: bool,
+    input_thread: Mutex<Option<JoinHandle<io::Result<()>>>>,
+    #[cfg(all(unix, feature = "jobserver"))]
+    jobserver_token: Mutex<Option<jobserver::JobserverToken>>,
+}
+
+impl CmdHandle {
+    fn wait(&self) -> io::Result<StageResult> {
+        let status = self.child.lock().unwrap().wait()?;
+        self.join_input_thread()?;
+        self.release_jobserver_token();
+        Ok(StageResult {
+            status: status,
+            checked: self.checked,
+        })
+    }
+
+    /// Poll the child with a non-blocking wait. Returns `None` without
+    /// disturbing anything if it's still running.
+    fn try_wait(&self) -> io::Result<Option<StageResult>> {
+        let status = self.child.lock().unwrap().try_wait()?;
+        match status {
+            Some(status) => {
+                self.join_input_thread()?;
+                self.release_jobserver_token();
+                Ok(Some(StageResult {
+                    status: status,
+                    checked: self.checked,
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Like `try_wait`, but never blocks on `child`'s lock the way
+    // `try_wait` does. `wait` holds that lock for as long as the process is
+    // running, so a watchdog that called `try_wait` to decide whether to
+    // kill would park on the same lock until the process exits on its own,
+    // defeating the timeout entirely. If the lock is currently held, that
+    // itself means some other thread is in the middle of `wait`-ing on a
+    // still-running child, so it's safe to conservatively report "still
+    // running" without taking the lock.
+    fn still_running(&self) -> bool {
+        match self.child.try_lock() {
+            Ok(mut child) => match child.try_wait() {
+                Ok(None) => true,
+                Ok(Some(_)) | Err(_) => false,
+            },
+            Err(_) => true,
+        }
+    }
+
+    // `wait` holds `child`'s lock for as long as the process is running, so
+    // killing through `Child::kill` (which needs that same lock) would
+    // deadlock against a concurrent `wait` -- exactly the case `.timeout()`
+    // depends on, since the watchdog thread calls this while some other
+    // thread is blocked in `wait`. Signaling by the PID we captured at
+    // spawn time sidesteps the lock entirely.
+    #[cfg(unix)]
+    fn kill(&self) -> io::Result<()> {
+        if unsafe { libc::kill(self.pid, libc::SIGKILL) } != 0 {
+            let err = io::Error::last_os_error();
+            // The child had already exited; nothing left to kill.
+            if err.raw_os_error() != Some(libc::ESRCH) {
+                return Err(err);
+            }
+        }
+        self.release_jobserver_token();
+        self.join_input_thread()
+    }
+
+    #[cfg(not(unix))]
+    fn kill(&self) -> io::Result<()> {
+        match self.child.lock().unwrap().kill() {
+            Ok(()) => {}
+            // The child had already exited; nothing left to kill.
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => {}
+            Err(e) => return Err(e),
+        }
+        self.release_jobserver_token();
+        self.join_input_thread()
+    }
+
+    fn join_input_thread(&self) -> io::Result<()> {
+        let mut slot = self.input_thread.lock().unwrap();
+        if let Some(thread) = slot.take() {
+            let write_result = thread.join().unwrap_or(Ok(()));
+            suppress_broken_pipe_errors(write_result)?;
+        }
+        Ok(())
+    }
+
+    /// Release this child's jobserver slot, if it was holding one, back to
+    /// the pool. A no-op once this has already been called, so `wait`,
+    /// `try_wait`, and `kill` can all call it once the child is confirmed
+    /// no longer running.
+    #[cfg(all(unix, feature = "jobserver"))]
+    fn release_jobserver_token(&self) {
+        self.jobserver_token.lock().unwrap().take();
+    }
+
+    #[cfg(not(all(unix, feature = "jobserver")))]
+    fn release_jobserver_token(&self) {}
+}
+
+enum HandleInner {
+    Cmd(CmdHandle),
+    Pipe(Box<HandleInner>, Box<HandleInner>),
+    Then {
+        // The left side of a `then` has already run to completion by the
+        // time a `Handle` exists; only the right side can still be alive.
+        left: StageResult,
+        right: Box<HandleInner>,
+    },
+    // `.unchecked()` wraps its subtree's handle in this, rather than
+    // threading an `unchecked` flag down through `IoContext` to each leaf.
+    // Marking the *result* unchecked here, after the inner subtree's own
+    // `combine()` precedence has already been applied, means an outer
+    // `.unchecked()` can never clobber an inner leaf's own checked-ness --
+    // see the comment on `IoContext`.
+    Unchecked(Box<HandleInner>),
+}
+
+impl HandleInner {
+    fn wait(&self) -> io::Result<StageResult> {
+        match *self {
+            HandleInner::Cmd(ref cmd) => cmd.wait(),
+            HandleInner::Pipe(ref left, ref right) => {
+                let left_result = left.wait()?;
+                let right_result = right.wait()?;
+                Ok(combine(left_result, right_result))
+            }
+            HandleInner::Then { left, ref right } => {
+                let right_result = right.wait()?;
+                Ok(combine(left, right_result))
+            }
+            HandleInner::Unchecked(ref inner) => {
+                let mut result = inner.wait()?;
+                result.checked = false;
+                Ok(result)
+            }
+        }
+    }
+
+    /// Poll every child with a non-blocking wait. Returns `None` unless
+    /// every child in the tree has already exited; a child that has
+    /// already been reaped (on an earlier poll) stays reaped, since
+    /// `CmdHandle::try_wait` only re-checks children that haven't finished.
+    fn try_wait(&self) -> io::Result<Option<StageResult>> {
+        match *self {
+            HandleInner::Cmd(ref cmd) => cmd.try_wait(),
+            HandleInner::Pipe(ref left, ref right) => {
+                // Always poll both sides, even if the left is already
+                // done, so the right side gets a chance to make progress.
+                match (left.try_wait()?, right.try_wait()?) {
+                    (Some(l), Some(r)) => Ok(Some(combine(l, r))),
+                    _ => Ok(None),
+                }
+            }
+            HandleInner::Then { left, ref right } => match right.try_wait()? {
+                Some(r) => Ok(Some(combine(left, r))),
+                None => Ok(None),
+            },
+            HandleInner::Unchecked(ref inner) => match inner.try_wait()? {
+                Some(mut result) => {
+                    result.checked = false;
+                    Ok(Some(result))
+                }
+                None => Ok(None),
+            },
+        }
+    }
+
+    fn kill(&self) -> io::Result<()> {
+        match *self {
+            HandleInner::Cmd(ref cmd) => cmd.kill(),
+            HandleInner::Pipe(ref left, ref right) => {
+                // Kill both sides even if one of them errors, so a failure
+                // on the left doesn't leave the right side running.
+                let left_result = left.kill();
+                let right_result = right.kill();
+                left_result.and(right_result)
+            }
+            HandleInner::Then { ref right, .. } => right.kill(),
+            HandleInner::Unchecked(ref inner) => inner.kill(),
+        }
+    }
+
+    // Used by the watchdog thread in `start()` to decide whether a timeout
+    // has actually elapsed while the tree is still running, without
+    // blocking on any child's lock the way `try_wait` would -- see the
+    // comment on `CmdHandle::still_running`.
+    fn still_running(&self) -> bool {
+        match *self {
+            HandleInner::Cmd(ref cmd) => cmd.still_running(),
+            HandleInner::Pipe(ref left, ref right) => {
+                left.still_running() || right.still_running()
+            }
+            HandleInner::Then { ref right, .. } => right.still_running(),
+            HandleInner::Unchecked(ref inner) => inner.still_running(),
+        }
+    }
+}
+
+/// Either a still-running capture thread, or the bytes it produced, cached
+/// so that calling `wait`/`try_wait` more than once keeps returning the
+/// same output.
+enum Capture {
+    None,
+    Pending(JoinHandle<io::Result<Vec<u8>>>),
+    Done(Vec<u8>),
+}
+
+impl Capture {
+    fn new(thread: Option<JoinHandle<io::Result<Vec<u8>>>>) -> Capture {
+        match thread {
+            Some(thread) => Capture::Pending(thread),
+            None => Capture::None,
+        }
+    }
+
+    fn collect(&mut self) -> io::Result<Vec<u8>> {
+        if let Capture::Pending(_) = *self {
+            let thread = match std::mem::replace(self, Capture::None) {
+                Capture::Pending(thread) => thread,
+                _ => unreachable!(),
+            };
+            let bytes = thread.join().unwrap_or(Ok(Vec::new()))?;
+            *self = Capture::Done(bytes);
+        }
+        match *self {
+            Capture::None => Ok(Vec::new()),
+            Capture::Done(ref bytes) => Ok(bytes.clone()),
+            Capture::Pending(_) => unreachable!(),
+        }
+    }
+}
+
+/// A handle to an expression that has been started with `Expression::start`.
+/// Dropping a `Handle` without waiting on it leaves the underlying
+/// processes running in the background.
+pub struct Handle {
+    inner: Arc<HandleInner>,
+    // Set by the watchdog thread spawned in `start()` if a `.timeout(...)`
+    // elapsed and it had to kill the expression. `wait`/`try_wait` check
+    // this before the ordinary checked-status logic, since a timeout should
+    // always be reported, even for an otherwise-unchecked expression.
+    timed_out: Arc<AtomicBool>,
+    stdout_capture: Mutex<Capture>,
+    stderr_capture: Mutex<Capture>,
+}
+
+impl Handle {
+    /// Wait for the expression to finish, and return its captured output.
+    /// If a `.timeout(...)` elapsed first, this returns `ErrorKind::Timeout`.
+    /// Otherwise, if the expression is checked (the common case) and it
+    /// exited with a non-zero status, this returns `ErrorKind::Status`.
+    pub fn wait(&self) -> Result<Output> {
+        let result = self.inner.wait()?;
+        let output = self.collect_output(result.status)?;
+        if self.timed_out.load(Ordering::SeqCst) {
+            bail!(ErrorKind::Timeout(output));
+        }
+        if result.checked && !result.status.success() {
+            bail!(ErrorKind::Status(output));
+        }
+        Ok(output)
+    }
+
+    /// Check whether the expression has finished, without blocking. Returns
+    /// `Ok(None)` if any child in the expression is still running, leaving
+    /// any already-captured output untouched so a later `wait`/`try_wait`
+    /// still sees it. Once every child has exited, this joins the
+    /// capture/writer threads and returns `Ok(Some(output))`, applying the
+    /// same checked/unchecked status precedence as `wait` (and the same
+    /// `ErrorKind::Timeout` handling, if a timeout is what caused this to
+    /// finish).
+    pub fn try_wait(&self) -> Result<Option<Output>> {
+        match self.inner.try_wait()? {
+            None => Ok(None),
+            Some(result) => {
+                let output = self.collect_output(result.status)?;
+                if self.timed_out.load(Ordering::SeqCst) {
+                    bail!(ErrorKind::Timeout(output));
+                }
+                if result.checked && !result.status.success() {
+                    bail!(ErrorKind::Status(output));
+                }
+                Ok(Some(output))
+            }
+        }
+    }
+
+    fn collect_output(&self, status: ExitStatus) -> io::Result<Output> {
+        let stdout = self.stdout_capture.lock().unwrap().collect()?;
+        let stderr = self.stderr_capture.lock().unwrap().collect()?;
+        Ok(Output {
+            status: status,
+            stdout: stdout,
+            stderr: stderr,
+        })
+    }
+
+    /// Kill every child process in the expression tree, recursively,
+    /// including both sides of any `pipe`/`then`, and join whatever
+    /// input-writer threads are still feeding them (swallowing the broken
+    /// pipe errors that causes). After calling this, `wait`/`try_wait` will
+    /// report the (probably signal-terminated) status of whatever was still
+    /// running.
+    pub fn kill(&self) -> io::Result<()> {
+        self.inner.kill()
+    }
+}
+
+#[cfg(feature = "async")]
+mod async_exec;
+#[cfg(feature = "async")]
+pub use async_exec::ReactorHandle;
+
+#[cfg(all(unix, feature = "jobserver"))]
+mod jobserver;
+#[cfg(all(unix, feature = "jobserver"))]
+pub use jobserver::{default_jobserver, set_default_jobserver, Jobserver};
+
+#[cfg(test)]
+mod test;
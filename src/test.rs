@@ -1,6 +1,11 @@
 extern crate tempdir;
 use self::tempdir::TempDir;
 
+#[cfg(feature = "async")]
+extern crate futures;
+#[cfg(feature = "async")]
+extern crate tokio_core;
+
 use os_pipe::FromFile;
 
 use super::*;
@@ -119,6 +124,44 @@ fn test_unchecked_in_pipe() {
     assert_eq!(1, output.status.code().unwrap());
 }
 
+// Poll `try_wait` until the expression finishes, the way a caller who
+// doesn't want to block the whole thread on `wait` would.
+fn poll_until_done(handle: &Handle) -> Result<Output> {
+    use std::time::Duration;
+    loop {
+        if let Some(output) = handle.try_wait()? {
+            return Ok(output);
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[test]
+fn test_unchecked_in_pipe_try_wait() {
+    // Same precedence rules as test_unchecked_in_pipe, but driven through
+    // try_wait() instead of wait(), to make sure polling doesn't disturb
+    // the checked/unchecked bookkeeping.
+    let zero = cmd!(path_to_exe("status"), "0");
+    let one = cmd!(path_to_exe("status"), "1");
+    let two = cmd!(path_to_exe("status"), "2");
+
+    // Right takes precedence over left.
+    let handle = one.pipe(two.clone()).unchecked().start().unwrap();
+    let output = poll_until_done(&handle).unwrap();
+    assert_eq!(2, output.status.code().unwrap());
+
+    // Except that checked on the left takes precedence over unchecked on
+    // the right.
+    let handle = one.pipe(two.unchecked()).unchecked().start().unwrap();
+    let output = poll_until_done(&handle).unwrap();
+    assert_eq!(1, output.status.code().unwrap());
+
+    // Except that if the right is a success, the left takes precedence.
+    let handle = one.unchecked().pipe(zero.unchecked()).unchecked().start().unwrap();
+    let output = poll_until_done(&handle).unwrap();
+    assert_eq!(1, output.status.code().unwrap());
+}
+
 #[test]
 fn test_pipe() {
     let output = sh("echo xxx").pipe(cmd!(path_to_exe("x_to_y"))).read().unwrap();
@@ -295,6 +338,36 @@ fn test_env() {
     assert_eq!("bar", output);
 }
 
+#[test]
+fn test_env_remove_and_clear() {
+    // An outer env_remove() wins over an inner env() for the same key,
+    // just like full_env's suppression, but layered instead of absolute:
+    // a still-later env() for the same key puts it right back.
+    let expr = cmd!(path_to_exe("print_env"), "foo")
+        .env("foo", "bar")
+        .env_remove("foo");
+    let output = expr.read().unwrap();
+    assert_eq!("", output);
+
+    let output = expr.env("foo", "baz").read().unwrap();
+    assert_eq!("baz", output);
+
+    // env_clear() drops everything inherited, but a later env() still
+    // layers normally on top of it.
+    let output = cmd!(path_to_exe("print_env"), "PATH")
+        .env_clear()
+        .read()
+        .unwrap();
+    assert_eq!("", output);
+
+    let output = cmd!(path_to_exe("print_env"), "foo")
+        .env_clear()
+        .env("foo", "bar")
+        .read()
+        .unwrap();
+    assert_eq!("bar", output);
+}
+
 #[test]
 fn test_full_env() {
     let var_name = "test_env_remove_var";
@@ -317,6 +390,53 @@ fn test_full_env() {
     assert_eq!("", output);
 }
 
+// Parity checks for start_async: the same swapping and broken-pipe
+// behavior test_swapping/test_broken_pipe cover on the blocking path
+// should hold when a leaf is driven by the reactor instead.
+#[cfg(feature = "async")]
+#[test]
+fn test_start_async_swapping() {
+    use self::futures::Future;
+    use self::tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let future = sh("echo hi")
+        .stdout_to_stderr()
+        .stderr_capture()
+        .start_async(&core.handle())
+        .unwrap();
+    let output = core.run(future).unwrap();
+    let stderr = str::from_utf8(&output.stderr).unwrap().trim();
+    assert_eq!("hi", stderr);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_start_async_broken_pipe() {
+    use self::futures::Future;
+    use self::tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let myvec = vec![0; 1_000_000];
+    let future = true_cmd().input(myvec).start_async(&core.handle()).unwrap();
+    core.run(future).unwrap();
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_start_async_rejects_timeout() {
+    use self::tokio_core::reactor::Core;
+    use std::time::Duration;
+
+    // start_async has no watchdog to enforce a deadline against, so it
+    // should return an error rather than silently ignore it.
+    let core = Core::new().unwrap();
+    let result = sh("echo hi")
+        .timeout(Duration::from_secs(1))
+        .start_async(&core.handle());
+    assert!(result.is_err());
+}
+
 #[test]
 fn test_broken_pipe() {
     // If the input writing thread fills up its pipe buffer, writing will block. If the process
@@ -341,6 +461,48 @@ fn test_silly() {
     ::IoValue::Null.try_clone().unwrap();
 }
 
+#[cfg(unix)]
+#[test]
+fn test_timeout() {
+    use std::time::{Duration, Instant};
+
+    // Regression test: the watchdog thread calls Handle::kill() while this
+    // thread is blocked inside Handle::wait(), so if kill() ever goes back
+    // to contending for the same lock wait() holds, this hangs forever
+    // instead of returning promptly.
+    let start = Instant::now();
+    let result = sh("sleep 10").timeout(Duration::from_millis(100)).run();
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    match result {
+        Err(Error(ErrorKind::Timeout(_), _)) => {}
+        _ => panic!("expected a Timeout error"),
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_kill_reports_signal() {
+    let handle = sh("sleep 10").unchecked().start().unwrap();
+    handle.kill().unwrap();
+    let output = handle.wait().unwrap();
+    assert_eq!(Some(9), ::signal(&output));
+}
+
+#[cfg(all(unix, feature = "jobserver"))]
+#[test]
+fn test_jobserver_pipe_does_not_deadlock() {
+    // A single-slot pool plus this invocation's own implicit slot is
+    // enough concurrency for a two-stage pipe. Before the fix, the second
+    // stage could never acquire a token, because the first was never
+    // reaped to release one back to the pool.
+    let pool = Jobserver::new(1).unwrap();
+    let left = sh("echo hi").jobserver(pool.clone());
+    let right = cmd!(path_to_exe("cat")).jobserver(pool);
+    let output = left.pipe(right).read().unwrap();
+    assert_eq!("hi", output);
+}
+
 #[test]
 fn test_path_sanitization() {
     // We don't do any chdir'ing in this process, because the tests runner is multithreaded,